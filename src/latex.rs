@@ -0,0 +1,557 @@
+use nodes::{AstNode, ListType, NodeCode, NodeValue, TableAlignment};
+use parser::{clamp_heading_level, ComrakOptions, ComrakPlugins};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::str;
+
+use crate::nodes::NodeHtmlBlock;
+
+/// Formats an AST as LaTeX, modified by the given options.
+pub fn format_document<'a>(
+    root: &'a AstNode<'a>,
+    options: &ComrakOptions,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    format_document_with_plugins(root, options, output, &ComrakPlugins::default())
+}
+
+/// Formats an AST as LaTeX, modified by the given options. Accepts custom plugins.
+pub fn format_document_with_plugins<'a>(
+    root: &'a AstNode<'a>,
+    options: &ComrakOptions,
+    output: &mut dyn Write,
+    plugins: &ComrakPlugins,
+) -> io::Result<()> {
+    LatexFormatter::new(options, output, plugins).format(root, false)
+}
+
+/// The heading levels that map onto LaTeX sectioning commands, indexed from
+/// level 1 (`\section`) through level 6 (`\subparagraph`).
+const SECTIONING_COMMANDS: [&str; 6] = [
+    "section",
+    "subsection",
+    "subsubsection",
+    "paragraph",
+    "subparagraph",
+    "subparagraph",
+];
+
+/// Restrict a fenced code block's info string to a safe identifier charset
+/// before it's interpolated into `lstlisting`'s `language=` argument,
+/// since the info string comes straight from untrusted Markdown and could
+/// otherwise inject stray `]`/`,` characters into the optional-argument
+/// list.
+fn sanitize_code_language(info: &[u8]) -> Option<String> {
+    let lang: String = info
+        .iter()
+        .take_while(|&&b| !b.is_ascii_whitespace())
+        .filter(|&&b| b.is_ascii_alphanumeric() || b == b'-' || b == b'+' || b == b'#')
+        .map(|&b| b as char)
+        .collect();
+    if lang.is_empty() {
+        None
+    } else {
+        Some(lang)
+    }
+}
+
+/// Whether `literal` contains a sequence that would prematurely close a
+/// `verbatim` or `lstlisting` environment if written into one verbatim.
+fn contains_environment_closer(literal: &[u8]) -> bool {
+    contains_subslice(literal, b"\\end{verbatim}") || contains_subslice(literal, b"\\end{lstlisting}")
+}
+
+fn contains_subslice(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.len() <= haystack.len() && haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Whether a node's children must be kept off the traversal stack entirely.
+/// `Image` has no construct to hold alt text (no open attribute like HTML's
+/// `alt="`), so its children are never rendered rather than leaking into
+/// the document body right after `\includegraphics{}`.
+fn suppresses_children(value: &NodeValue) -> bool {
+    matches!(value, NodeValue::Image(..))
+}
+
+/// Escapes LaTeX special characters (`\ # $ % & _ { } ^ ~`) in `buffer` and
+/// writes the result to `output`. Factored out of `LatexFormatter::escape`
+/// so it can be exercised without an `AstNode` to drive a full formatter.
+fn latex_escape(output: &mut dyn Write, buffer: &[u8]) -> io::Result<()> {
+    let mut offset = 0;
+    for (i, &byte) in buffer.iter().enumerate() {
+        let esc: &[u8] = match byte {
+            b'\\' => b"\\textbackslash{}",
+            b'#' => b"\\#",
+            b'$' => b"\\$",
+            b'%' => b"\\%",
+            b'&' => b"\\&",
+            b'_' => b"\\_",
+            b'{' => b"\\{",
+            b'}' => b"\\}",
+            b'^' => b"\\textasciicircum{}",
+            b'~' => b"\\textasciitilde{}",
+            _ => continue,
+        };
+        output.write_all(&buffer[offset..i])?;
+        output.write_all(esc)?;
+        offset = i + 1;
+    }
+    output.write_all(&buffer[offset..])?;
+    Ok(())
+}
+
+/// Renders a fenced code block's `entering` output: either an escaped
+/// fallback inside a `quote` environment (when the literal would otherwise
+/// break out of `verbatim`/`lstlisting`) or the literal written verbatim
+/// inside the appropriate environment for its sanitized language.
+fn write_code_block(output: &mut dyn Write, info: &[u8], literal: &[u8]) -> io::Result<()> {
+    if contains_environment_closer(literal) {
+        // `verbatim`/`lstlisting` content can't be escaped, so a literal
+        // containing `\end{verbatim}` or `\end{lstlisting}` would close the
+        // environment early and let the rest of the block's content inject
+        // raw LaTeX. Fall back to an escaped block inside `quote` instead of
+        // a one-argument command like `\texttt{}`, since a blank line in the
+        // literal would otherwise end the paragraph before the command's
+        // argument is complete.
+        output.write_all(b"\\begin{quote}\\ttfamily\n")?;
+        latex_escape(output, literal)?;
+        output.write_all(b"\\end{quote}\n\n")?;
+    } else {
+        match sanitize_code_language(info) {
+            Some(ref lang) => writeln!(output, "\\begin{{lstlisting}}[language={}]", lang)?,
+            None => output.write_all(b"\\begin{verbatim}\n")?,
+        }
+        output.write_all(literal)?;
+        match sanitize_code_language(info) {
+            Some(_) => output.write_all(b"\\end{lstlisting}\n\n")?,
+            None => output.write_all(b"\\end{verbatim}\n\n")?,
+        }
+    }
+    Ok(())
+}
+
+struct LatexFormatter<'o> {
+    output: &'o mut dyn Write,
+    options: &'o ComrakOptions,
+    plugins: &'o ComrakPlugins<'o>,
+}
+
+impl<'o> LatexFormatter<'o> {
+    fn new(
+        options: &'o ComrakOptions,
+        output: &'o mut dyn Write,
+        plugins: &'o ComrakPlugins,
+    ) -> Self {
+        LatexFormatter {
+            options,
+            output,
+            plugins,
+        }
+    }
+
+    fn escape(&mut self, buffer: &[u8]) -> io::Result<()> {
+        latex_escape(self.output, buffer)
+    }
+
+    fn rewrite_url<'b>(&self, url: &'b [u8]) -> Cow<'b, str> {
+        let url = str::from_utf8(url).unwrap_or_default();
+        match self.plugins.render.url_rewriter {
+            Some(rewriter) => Cow::Owned(rewriter(url).into_owned()),
+            None => Cow::Borrowed(url),
+        }
+    }
+
+    fn format<'a>(&mut self, node: &'a AstNode<'a>, plain: bool) -> io::Result<()> {
+        // Traverse the AST iteratively using a work stack, with pre- and
+        // post-child-traversal phases, mirroring the approach used by the
+        // XML formatter.
+
+        enum Phase {
+            Pre,
+            Post,
+        }
+        let mut stack = vec![(node, plain, Phase::Pre)];
+
+        while let Some((node, plain, phase)) = stack.pop() {
+            match phase {
+                Phase::Pre => {
+                    // Images have no construct to hold their alt text (no
+                    // open attribute like HTML's `alt="`), so their
+                    // children are never pushed onto the stack and so
+                    // never leak into the document body.
+                    let suppress_children = suppresses_children(&node.data.borrow().value);
+
+                    let new_plain;
+                    if plain {
+                        match node.data.borrow().value {
+                            NodeValue::Text(ref literal)
+                            | NodeValue::Code(NodeCode { ref literal, .. })
+                            | NodeValue::HtmlInline(ref literal) => {
+                                self.escape(literal)?;
+                            }
+                            NodeValue::LineBreak | NodeValue::SoftBreak => {
+                                self.output.write_all(b" ")?;
+                            }
+                            _ => (),
+                        }
+                        new_plain = plain;
+                    } else {
+                        stack.push((node, false, Phase::Post));
+                        new_plain = self.format_node(node, true)?;
+                    }
+
+                    if !suppress_children {
+                        for ch in node.reverse_children() {
+                            stack.push((ch, new_plain, Phase::Pre));
+                        }
+                    }
+                }
+                Phase::Post => {
+                    debug_assert!(!plain);
+                    self.format_node(node, false)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn format_node<'a>(&mut self, node: &'a AstNode<'a>, entering: bool) -> io::Result<bool> {
+        match node.data.borrow().value {
+            NodeValue::Document => (),
+            NodeValue::FrontMatter(_) => (),
+            NodeValue::Text(ref literal) => {
+                if entering {
+                    self.escape(literal)?;
+                }
+            }
+            NodeValue::HtmlBlock(NodeHtmlBlock { ref literal, .. }) => {
+                if entering {
+                    self.escape(literal)?;
+                    self.output.write_all(b"\n\n")?;
+                }
+            }
+            NodeValue::HtmlInline(ref literal) => {
+                if entering {
+                    self.escape(literal)?;
+                }
+            }
+            NodeValue::Paragraph => {
+                if !entering {
+                    self.output.write_all(b"\n\n")?;
+                }
+            }
+            NodeValue::Heading(ref nch) => {
+                if entering {
+                    let level =
+                        clamp_heading_level(nch.level as i32 + self.options.render.heading_offset);
+                    write!(
+                        self.output,
+                        "\\{}{{",
+                        SECTIONING_COMMANDS[(level - 1) as usize]
+                    )?;
+                } else {
+                    self.output.write_all(b"}\n\n")?;
+                }
+            }
+            NodeValue::ThematicBreak => {
+                if entering {
+                    self.output.write_all(b"\\noindent\\hrulefill\n\n")?;
+                }
+            }
+            NodeValue::BlockQuote => {
+                if entering {
+                    self.output.write_all(b"\\begin{quote}\n")?;
+                } else {
+                    self.output.write_all(b"\\end{quote}\n\n")?;
+                }
+            }
+            NodeValue::List(ref nl) => {
+                let env = if nl.list_type == ListType::Bullet {
+                    "itemize"
+                } else {
+                    "enumerate"
+                };
+                if entering {
+                    writeln!(self.output, "\\begin{{{}}}", env)?;
+                } else {
+                    writeln!(self.output, "\\end{{{}}}\n", env)?;
+                }
+            }
+            NodeValue::Item(..) => {
+                if entering {
+                    self.output.write_all(b"\\item ")?;
+                } else {
+                    self.output.write_all(b"\n")?;
+                }
+            }
+            NodeValue::CodeBlock(ref ncb) => {
+                if entering {
+                    write_code_block(self.output, &ncb.info, &ncb.literal)?;
+                }
+            }
+            NodeValue::Code(NodeCode { ref literal, .. }) => {
+                if entering {
+                    self.output.write_all(b"\\texttt{")?;
+                    self.escape(literal)?;
+                    self.output.write_all(b"}")?;
+                }
+            }
+            NodeValue::Emph => {
+                self.output
+                    .write_all(if entering { b"\\emph{" } else { b"}" })?;
+            }
+            NodeValue::Strong => {
+                self.output
+                    .write_all(if entering { b"\\textbf{" } else { b"}" })?;
+            }
+            NodeValue::Strikethrough => {
+                self.output
+                    .write_all(if entering { b"\\sout{" } else { b"}" })?;
+            }
+            NodeValue::Superscript => {
+                self.output
+                    .write_all(if entering { b"\\textsuperscript{" } else { b"}" })?;
+            }
+            NodeValue::LineBreak => {
+                if entering {
+                    self.output.write_all(b"\\\\\n")?;
+                }
+            }
+            NodeValue::SoftBreak => {
+                if entering {
+                    if self.options.render.hardbreaks {
+                        self.output.write_all(b"\\\\\n")?;
+                    } else {
+                        self.output.write_all(b"\n")?;
+                    }
+                }
+            }
+            NodeValue::Link(ref nl) => {
+                if entering {
+                    let url = self.rewrite_url(&nl.url);
+                    self.output.write_all(b"\\href{")?;
+                    self.escape(url.as_bytes())?;
+                    self.output.write_all(b"}{")?;
+                } else {
+                    self.output.write_all(b"}")?;
+                }
+            }
+            NodeValue::Image(ref nl) => {
+                if entering {
+                    let url = self.rewrite_url(&nl.url);
+                    self.output.write_all(b"\\includegraphics{")?;
+                    self.escape(url.as_bytes())?;
+                    self.output.write_all(b"}")?;
+                }
+            }
+            NodeValue::Table(..) => {
+                if entering {
+                    let alignments = match node.data.borrow().value {
+                        NodeValue::Table(ref alignments) => alignments.clone(),
+                        _ => unreachable!(),
+                    };
+                    let spec: String = alignments
+                        .iter()
+                        .map(|a| match *a {
+                            TableAlignment::Left => 'l',
+                            TableAlignment::Right => 'r',
+                            TableAlignment::Center => 'c',
+                            TableAlignment::None => 'l',
+                        })
+                        .collect();
+                    writeln!(self.output, "\\begin{{tabular}}{{{}}}", spec)?;
+                } else {
+                    self.output.write_all(b"\\end{tabular}\n\n")?;
+                }
+            }
+            NodeValue::TableRow(header) => {
+                if !entering {
+                    self.output.write_all(b" \\\\\n")?;
+                    if header {
+                        self.output.write_all(b"\\hline\n")?;
+                    }
+                }
+            }
+            NodeValue::TableCell => {
+                if !entering {
+                    if node.next_sibling().is_some() {
+                        self.output.write_all(b" & ")?;
+                    }
+                }
+            }
+            NodeValue::FootnoteDefinition(_) => (),
+            NodeValue::FootnoteReference(ref r) => {
+                if entering {
+                    self.output.write_all(b"\\footnotemark[")?;
+                    self.escape(r)?;
+                    self.output.write_all(b"]")?;
+                }
+            }
+            NodeValue::TaskItem(checked) => {
+                if entering {
+                    let mark = if checked { r"$\boxtimes$ " } else { r"$\square$ " };
+                    self.output.write_all(b"\\item ")?;
+                    self.output.write_all(mark.as_bytes())?;
+                } else {
+                    self.output.write_all(b"\n")?;
+                }
+            }
+            NodeValue::DescriptionList => (),
+            NodeValue::DescriptionItem(..) => (),
+            NodeValue::DescriptionTerm => {
+                if !entering {
+                    self.output.write_all(b"\n")?;
+                }
+            }
+            NodeValue::DescriptionDetails => {
+                if entering {
+                    self.output.write_all(b"\\quad ")?;
+                } else {
+                    self.output.write_all(b"\n")?;
+                }
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::NodeLink;
+
+    #[test]
+    fn escape_handles_all_latex_special_characters() {
+        let options = ComrakOptions::default();
+        let plugins = ComrakPlugins::default();
+        let mut out = Vec::new();
+        let mut formatter = LatexFormatter::new(&options, &mut out, &plugins);
+
+        formatter.escape(b"a & b # c $ d % e _ f { g } h ^ i ~ j \\ k").unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a \\& b \\# c \\$ d \\% e \\_ f \\{ g \\} h \\textasciicircum{} i \\textasciitilde{} j \\textbackslash{} k"
+        );
+    }
+
+    #[test]
+    fn sanitize_code_language_strips_unsafe_characters() {
+        assert_eq!(
+            sanitize_code_language(b"rust"),
+            Some("rust".to_string())
+        );
+        assert_eq!(
+            sanitize_code_language(b"c++"),
+            Some("c++".to_string())
+        );
+        assert_eq!(
+            sanitize_code_language(b"rust]{evil}["),
+            Some("rustevil".to_string())
+        );
+        assert_eq!(sanitize_code_language(b""), None);
+    }
+
+    #[test]
+    fn code_block_breakout_attempt_is_detected() {
+        assert!(contains_environment_closer(
+            b"fine\n\\end{verbatim}\n\\section{injected}"
+        ));
+        assert!(!contains_environment_closer(b"perfectly ordinary code"));
+    }
+
+    #[test]
+    fn link_destination_is_escaped_after_rewriting() {
+        let options = ComrakOptions::default();
+        let rewriter: &dyn Fn(&str) -> Cow<str> =
+            &|url: &str| Cow::Owned(format!("{}&utm_source=test", url));
+        let plugins = ComrakPlugins {
+            render: crate::parser::ComrakPluginsRender {
+                url_rewriter: Some(rewriter),
+            },
+        };
+        let mut out = Vec::new();
+        let mut formatter = LatexFormatter::new(&options, &mut out, &plugins);
+
+        let url = formatter.rewrite_url(b"https://example.com/page").into_owned();
+        formatter.escape(url.as_bytes()).unwrap();
+
+        // The raw `&` and `_` introduced by the rewriter must come out
+        // escaped, or the generated LaTeX fails to compile.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "https://example.com/page\\&utm\\_source=test"
+        );
+    }
+
+    #[test]
+    fn image_children_are_the_only_ones_suppressed() {
+        let image = NodeValue::Image(NodeLink {
+            url: b"cat.png".to_vec(),
+            title: Vec::new(),
+        });
+        assert!(suppresses_children(&image));
+        assert!(!suppresses_children(&NodeValue::Paragraph));
+    }
+
+    #[test]
+    fn html_literal_is_escaped_not_written_raw() {
+        let options = ComrakOptions::default();
+        let plugins = ComrakPlugins::default();
+        let mut out = Vec::new();
+        let mut formatter = LatexFormatter::new(&options, &mut out, &plugins);
+
+        // Raw HTML can carry `%`/`&`/`_` that would otherwise break LaTeX
+        // compilation or silently corrupt the output if written unescaped.
+        formatter.escape(b"<span class=\"a_b\">100% & more</span>").unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<span class=\"a\\_b\">100\\% \\& more</span>"
+        );
+    }
+
+    #[test]
+    fn code_block_breakout_attempt_falls_back_to_a_blank_line_tolerant_environment() {
+        let mut out = Vec::new();
+        // A `quote` environment (unlike a one-argument macro such as
+        // `\texttt{}`) must survive a blank line in the literal without a
+        // fatal "Paragraph ended before ... was complete" error.
+        write_code_block(
+            &mut out,
+            b"rust",
+            b"fn main() {}\n\n\\end{verbatim}\nmalicious",
+        )
+        .unwrap();
+        let rendered = String::from_utf8(out).unwrap();
+
+        assert!(rendered.starts_with("\\begin{quote}\\ttfamily\n"));
+        assert!(rendered.ends_with("\\end{quote}\n\n"));
+        assert!(
+            rendered.contains("fn main() \\{\\}\n\n\\textbackslash{}end"),
+            "blank line must survive untouched"
+        );
+        // The dangerous sequence must come out escaped, not as a literal
+        // environment-closing command.
+        assert!(!rendered.contains("\\end{verbatim}\nmalicious"));
+    }
+
+    #[test]
+    fn code_block_without_breakout_uses_verbatim_or_lstlisting() {
+        let mut plain = Vec::new();
+        write_code_block(&mut plain, b"", b"ordinary code").unwrap();
+        assert_eq!(
+            String::from_utf8(plain).unwrap(),
+            "\\begin{verbatim}\nordinary code\\end{verbatim}\n\n"
+        );
+
+        let mut tagged = Vec::new();
+        write_code_block(&mut tagged, b"rust", b"fn main() {}").unwrap();
+        assert_eq!(
+            String::from_utf8(tagged).unwrap(),
+            "\\begin{lstlisting}[language=rust]\nfn main() {}\\end{lstlisting}\n\n"
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+
+/// Options for parsing and rendering Markdown with comrak.
+#[derive(Default, Debug, Clone)]
+pub struct ComrakOptions {
+    /// Options affecting rendering.
+    pub render: ComrakRenderOptions,
+}
+
+/// Options affecting how an AST is rendered back out, shared by every
+/// output backend (HTML, CommonMark-XML, LaTeX, ...).
+#[derive(Debug, Clone)]
+pub struct ComrakRenderOptions {
+    /// Include a `sourcepos` attribute on every element, recording its
+    /// position in the source document.
+    pub sourcepos: bool,
+
+    /// Render `\n` line breaks inside paragraphs as hard breaks.
+    pub hardbreaks: bool,
+
+    /// Shift every heading level by this signed amount before rendering,
+    /// e.g. so a `#` in an embedded fragment can be rendered as a level-2
+    /// heading instead of colliding with the host document's title. The
+    /// resulting level always saturates to the `1..=6` range.
+    pub heading_offset: i32,
+
+    /// Cap the number of bytes written to the output after the XML prolog;
+    /// tags and indentation count toward the cap, not only text content.
+    /// Only the CommonMark-XML formatter applies this cap; the LaTeX
+    /// formatter ignores it. Once
+    /// exceeded, rendering stops accepting new content but still closes
+    /// every element left open on the traversal stack, so the truncated
+    /// output remains well-formed, and an ellipsis marker is appended at
+    /// the cut point.
+    pub max_length: Option<usize>,
+}
+
+impl Default for ComrakRenderOptions {
+    fn default() -> Self {
+        ComrakRenderOptions {
+            sourcepos: false,
+            hardbreaks: false,
+            heading_offset: 0,
+            max_length: None,
+        }
+    }
+}
+
+/// Clamp a heading level (after applying any configured offset) to the
+/// valid `1..=6` range, so a deeply offset or nested heading can never
+/// produce a malformed level in the output.
+pub fn clamp_heading_level(level: i32) -> u32 {
+    level.max(1).min(6) as u32
+}
+
+/// Plugins that customize rendering without post-processing the output.
+#[derive(Default)]
+pub struct ComrakPlugins<'a> {
+    /// Render-time plugin hooks.
+    pub render: ComrakPluginsRender<'a>,
+}
+
+/// Render-time plugin hooks, shared by every output backend.
+#[derive(Default)]
+pub struct ComrakPluginsRender<'a> {
+    /// Called with every link and image destination before it is written
+    /// to the output, letting callers rewrite or neutralize URLs (e.g.
+    /// proxying relative links, stripping `src` on untrusted images, or
+    /// blocking `javascript:` URLs).
+    pub url_rewriter: Option<&'a dyn Fn(&str) -> Cow<str>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_heading_level_saturates_at_bounds() {
+        assert_eq!(clamp_heading_level(1), 1);
+        assert_eq!(clamp_heading_level(6), 6);
+        assert_eq!(clamp_heading_level(7), 6);
+        assert_eq!(clamp_heading_level(0), 1);
+        assert_eq!(clamp_heading_level(-5), 1);
+    }
+
+    #[test]
+    fn clamp_heading_level_applies_offset() {
+        // A level-1 heading nudged down by 2 lands at level 3, and a
+        // level-5 heading nudged down by 3 saturates at level 6 rather than
+        // overflowing to 8.
+        assert_eq!(clamp_heading_level(1 + 2), 3);
+        assert_eq!(clamp_heading_level(5 + 3), 6);
+    }
+
+    #[test]
+    fn url_rewriter_hook_is_invoked() {
+        let rewriter: &dyn Fn(&str) -> Cow<str> =
+            &|url: &str| Cow::Owned(format!("https://proxy/{}", url));
+        let plugins = ComrakPlugins {
+            render: ComrakPluginsRender {
+                url_rewriter: Some(rewriter),
+            },
+        };
+
+        let rewritten = (plugins.render.url_rewriter.unwrap())("img.png");
+        assert_eq!(rewritten, "https://proxy/img.png");
+    }
+}
@@ -1,12 +1,13 @@
 use ctype::isspace;
 use nodes::{AstNode, ListType, NodeCode, NodeValue, TableAlignment};
-use parser::{ComrakOptions, ComrakPlugins};
+use parser::{clamp_heading_level, ComrakOptions, ComrakPlugins};
 use regex::Regex;
 use scanners;
 use std::borrow::Cow;
 use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
+use std::rc::Rc;
 use std::str;
 use strings::build_opening_tag;
 
@@ -31,7 +32,139 @@ pub fn format_document_with_plugins<'a>(
     output.write_all(b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n")?;
     output.write_all(b"<!DOCTYPE document SYSTEM \"CommonMark.dtd\">\n")?;
 
-    XmlFormatter::new(options, output, plugins).format(root, false)
+    match options.render.max_length {
+        Some(limit) => {
+            let truncated = Rc::new(Cell::new(false));
+            let mut limited = LimitedWriter::new(output, limit, Rc::clone(&truncated));
+            XmlFormatter::new(options, &mut limited, plugins, Some(truncated)).format(root, false)
+        }
+        None => XmlFormatter::new(options, output, plugins, None).format(root, false),
+    }
+}
+
+/// Step an index back to the start of a UTF-8 character if it currently
+/// sits in the middle of a multi-byte sequence, so cutting `buf` at the
+/// returned index never splits a codepoint. `index` must be `<= buf.len()`.
+fn floor_char_boundary(buf: &[u8], mut index: usize) -> usize {
+    while index > 0 && index < buf.len() && (buf[index] & 0xC0) == 0x80 {
+        index -= 1;
+    }
+    index
+}
+
+/// A `Write` sink that passes bytes through until a configured budget of
+/// bytes has been written (tags and indentation count toward the budget,
+/// not only text content), then silently drops further content
+/// (appending an ellipsis marker at the point of truncation). Wrapping the
+/// formatter's output in this lets `XmlFormatter::format` keep driving its
+/// pre/post work stack to completion, so every element left open on the
+/// stack still gets its closing tag written after the cutoff.
+struct LimitedWriter<'o> {
+    inner: &'o mut dyn Write,
+    limit: usize,
+    written: usize,
+    truncated: Rc<Cell<bool>>,
+}
+
+impl<'o> LimitedWriter<'o> {
+    fn new(inner: &'o mut dyn Write, limit: usize, truncated: Rc<Cell<bool>>) -> Self {
+        LimitedWriter {
+            inner,
+            limit,
+            written: 0,
+            truncated,
+        }
+    }
+}
+
+impl<'o> Write for LimitedWriter<'o> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.truncated.get() {
+            // The formatter's pre-order phase refuses to open anything new
+            // once truncated, so every write that reaches us past this
+            // point is a closing tag from the post-order unwind. Those have
+            // to land unmetered or the output is left permanently malformed.
+            self.inner.write_all(buf)?;
+            return Ok(buf.len());
+        }
+
+        if self.written + buf.len() > self.limit {
+            let allowed = self.limit.saturating_sub(self.written);
+            let allowed = floor_char_boundary(buf, allowed);
+            self.inner.write_all(&buf[..allowed])?;
+            self.written += allowed;
+            self.truncated.set(true);
+            self.inner.write_all(b"...")?;
+            return Ok(buf.len());
+        }
+
+        self.inner.write_all(buf)?;
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The XML element name for a node, overriding `NodeValue::xml_node_name`
+/// for a header `TableRow` so header and body rows are distinguishable as
+/// `<table_header>`/`<table_row>` elements rather than identical elements
+/// differing only by attribute.
+fn xml_tag_name(value: &NodeValue) -> Cow<'static, str> {
+    match *value {
+        NodeValue::TableRow(true) => Cow::Borrowed("table_header"),
+        ref other => Cow::Borrowed(other.xml_node_name()),
+    }
+}
+
+/// Escapes `&`, `<`, `>` and `"` in `buffer` and writes the result to
+/// `output`. Factored out of `XmlFormatter::escape` so it can be exercised
+/// (and reused by free-standing attribute writers) without an `AstNode` to
+/// drive a full formatter.
+fn xml_escape(output: &mut dyn Write, buffer: &[u8]) -> io::Result<()> {
+    lazy_static! {
+        static ref XML_SAFE: [bool; 256] = {
+            let mut a = [true; 256];
+            for &c in b"&<>\"".iter() {
+                a[c as usize] = false;
+            }
+            a
+        };
+    }
+    let mut offset = 0;
+    for (i, &byte) in buffer.iter().enumerate() {
+        if !XML_SAFE[byte as usize] {
+            let esc: &[u8] = match byte {
+                b'"' => b"&quot;",
+                b'&' => b"&amp;",
+                b'<' => b"&lt;",
+                b'>' => b"&gt;",
+                _ => unreachable!(),
+            };
+            output.write_all(&buffer[offset..i])?;
+            output.write_all(esc)?;
+            offset = i + 1;
+        }
+    }
+    output.write_all(&buffer[offset..])?;
+    Ok(())
+}
+
+/// The `name="..."` attribute shared by `FootnoteDefinition` and
+/// `FootnoteReference` elements.
+fn write_footnote_name_attr(output: &mut dyn Write, name: &[u8]) -> io::Result<()> {
+    output.write_all(b" name=\"")?;
+    xml_escape(output, name)?;
+    output.write_all(b"\"")?;
+    Ok(())
+}
+
+/// The `completed="..."` attribute on a `TaskItem` element, recording
+/// whether the task is checked off.
+fn write_task_item_attr(output: &mut dyn Write, checked: bool) -> io::Result<()> {
+    write!(output, " completed=\"{}\"", checked)
 }
 
 struct XmlFormatter<'o> {
@@ -39,6 +172,7 @@ struct XmlFormatter<'o> {
     options: &'o ComrakOptions,
     plugins: &'o ComrakPlugins<'o>,
     indent: u32,
+    truncated: Option<Rc<Cell<bool>>>,
 }
 
 impl<'o> XmlFormatter<'o> {
@@ -46,42 +180,23 @@ impl<'o> XmlFormatter<'o> {
         options: &'o ComrakOptions,
         output: &'o mut dyn Write,
         plugins: &'o ComrakPlugins,
+        truncated: Option<Rc<Cell<bool>>>,
     ) -> Self {
         XmlFormatter {
             options,
             output,
             plugins,
             indent: 0,
+            truncated,
         }
     }
 
+    fn is_truncated(&self) -> bool {
+        self.truncated.as_ref().map_or(false, |t| t.get())
+    }
+
     fn escape(&mut self, buffer: &[u8]) -> io::Result<()> {
-        lazy_static! {
-            static ref XML_SAFE: [bool; 256] = {
-                let mut a = [true; 256];
-                for &c in b"&<>\"".iter() {
-                    a[c as usize] = false;
-                }
-                a
-            };
-        }
-        let mut offset = 0;
-        for (i, &byte) in buffer.iter().enumerate() {
-            if !XML_SAFE[byte as usize] {
-                let esc: &[u8] = match byte {
-                    b'"' => b"&quot;",
-                    b'&' => b"&amp;",
-                    b'<' => b"&lt;",
-                    b'>' => b"&gt;",
-                    _ => unreachable!(),
-                };
-                self.output.write_all(&buffer[offset..i])?;
-                self.output.write_all(esc)?;
-                offset = i + 1;
-            }
-        }
-        self.output.write_all(&buffer[offset..])?;
-        Ok(())
+        xml_escape(self.output, buffer)
     }
 
     fn format<'a>(&mut self, node: &'a AstNode<'a>, plain: bool) -> io::Result<()> {
@@ -100,6 +215,13 @@ impl<'o> XmlFormatter<'o> {
         while let Some((node, plain, phase)) = stack.pop() {
             match phase {
                 Phase::Pre => {
+                    if self.is_truncated() {
+                        // Leave already-opened ancestors' Post frames on the
+                        // stack so their closing tags still get written, but
+                        // don't open anything new past the truncation point.
+                        continue;
+                    }
+
                     let new_plain;
                     if plain {
                         match node.data.borrow().value {
@@ -160,7 +282,8 @@ impl<'o> XmlFormatter<'o> {
 
             let ast = node.data.borrow();
 
-            write!(self.output, "<{}", ast.value.xml_node_name())?;
+            let tag_name = xml_tag_name(&ast.value);
+            write!(self.output, "<{}", tag_name)?;
 
             if self.options.render.sourcepos && ast.start_line != 0 {
                 write!(
@@ -182,7 +305,7 @@ impl<'o> XmlFormatter<'o> {
                 | NodeValue::HtmlInline(ref literal) => {
                     self.output.write_all(b" xml:space=\"preserve\">")?;
                     self.escape(literal)?;
-                    write!(self.output, "</{}", ast.value.xml_node_name())?;
+                    write!(self.output, "</{}", tag_name)?;
                     was_literal = true;
                 }
                 NodeValue::List(ref nl) => {
@@ -206,7 +329,9 @@ impl<'o> XmlFormatter<'o> {
                 NodeValue::DescriptionTerm => {}
                 NodeValue::DescriptionDetails => {}
                 NodeValue::Heading(ref nch) => {
-                    write!(self.output, " level=\"{}\"", nch.level)?;
+                    let level =
+                        clamp_heading_level(nch.level as i32 + self.options.render.heading_offset);
+                    write!(self.output, " level=\"{}\"", level)?;
                 }
                 NodeValue::CodeBlock(ref ncb) => {
                     if !ncb.info.is_empty() {
@@ -216,7 +341,7 @@ impl<'o> XmlFormatter<'o> {
                     }
                     self.output.write_all(b" xml:space=\"preserve\">")?;
                     self.escape(&ncb.literal)?;
-                    write!(self.output, "</{}", ast.value.xml_node_name())?;
+                    write!(self.output, "</{}", tag_name)?;
                     was_literal = true;
                 }
                 NodeValue::ThematicBreak => {}
@@ -228,132 +353,54 @@ impl<'o> XmlFormatter<'o> {
                 NodeValue::Strikethrough => {}
                 NodeValue::Superscript => {}
                 NodeValue::Link(ref nl) | NodeValue::Image(ref nl) => {
+                    let url = str::from_utf8(&nl.url).unwrap_or_default();
+                    let url = match self.plugins.render.url_rewriter {
+                        Some(rewriter) => rewriter(url),
+                        None => Cow::Borrowed(url),
+                    };
                     self.output.write_all(b" destination=\"")?;
-                    self.escape(&nl.url)?;
+                    self.escape(url.as_bytes())?;
                     self.output.write_all(b"\" title=\"")?;
                     self.escape(&nl.title)?;
                     self.output.write_all(b"\"")?;
                 }
-                NodeValue::Table(..) => {
-                    // TODO
-                    // if entering {
-                    //     self.output.write_all(b"<table>\n")?;
-                    // } else {
-                    //     if !node
-                    //         .last_child()
-                    //         .unwrap()
-                    //         .same_node(node.first_child().unwrap())
-                    //     {
-                    //         self.output.write_all(b"</tbody>\n")?;
-                    //     }
-                    //     self.output.write_all(b"</table>\n")?;
-                    // }
-                }
-                NodeValue::TableRow(header) => {
-                    // TODO
-                    // if entering {
-                    //     if header {
-                    //         self.output.write_all(b"<thead>\n")?;
-                    //     } else if let Some(n) = node.previous_sibling() {
-                    //         if let NodeValue::TableRow(true) = n.data.borrow().value {
-                    //             self.output.write_all(b"<tbody>\n")?;
-                    //         }
-                    //     }
-                    //     self.output.write_all(b"<tr>")?;
-                    // } else {
-                    //     self.output.write_all(b"</tr>")?;
-                    //     if header {
-                    //         self.output.write_all(b"</thead>")?;
-                    //     }
-                    // }
-                }
+                NodeValue::Table(..) => {}
+                NodeValue::TableRow(_) => {}
                 NodeValue::TableCell => {
-                    // TODO
-                    // let row = &node.parent().unwrap().data.borrow().value;
-                    // let in_header = match *row {
-                    //     NodeValue::TableRow(header) => header,
-                    //     _ => panic!(),
-                    // };
-
-                    // let table = &node.parent().unwrap().parent().unwrap().data.borrow().value;
-                    // let alignments = match *table {
-                    //     NodeValue::Table(ref alignments) => alignments,
-                    //     _ => panic!(),
-                    // };
-
-                    // if entering {
-                    //     if in_header {
-                    //         self.output.write_all(b"<th")?;
-                    //     } else {
-                    //         self.output.write_all(b"<td")?;
-                    //     }
-
-                    //     let mut start = node.parent().unwrap().first_child().unwrap();
-                    //     let mut i = 0;
-                    //     while !start.same_node(node) {
-                    //         i += 1;
-                    //         start = start.next_sibling().unwrap();
-                    //     }
-
-                    //     match alignments[i] {
-                    //         TableAlignment::Left => {
-                    //             self.output.write_all(b" align=\"left\"")?;
-                    //         }
-                    //         TableAlignment::Right => {
-                    //             self.output.write_all(b" align=\"right\"")?;
-                    //         }
-                    //         TableAlignment::Center => {
-                    //             self.output.write_all(b" align=\"center\"")?;
-                    //         }
-                    //         TableAlignment::None => (),
-                    //     }
-
-                    //     self.output.write_all(b">")?;
-                    // } else if in_header {
-                    //     self.output.write_all(b"</th>")?;
-                    // } else {
-                    //     self.output.write_all(b"</td>")?;
-                    // }
+                    let table = &node.parent().unwrap().parent().unwrap().data.borrow().value;
+                    let alignments = match *table {
+                        NodeValue::Table(ref alignments) => alignments,
+                        _ => panic!(),
+                    };
+
+                    let mut start = node.parent().unwrap().first_child().unwrap();
+                    let mut i = 0;
+                    while !start.same_node(node) {
+                        i += 1;
+                        start = start.next_sibling().unwrap();
+                    }
+
+                    match alignments[i] {
+                        TableAlignment::Left => {
+                            self.output.write_all(b" align=\"left\"")?;
+                        }
+                        TableAlignment::Right => {
+                            self.output.write_all(b" align=\"right\"")?;
+                        }
+                        TableAlignment::Center => {
+                            self.output.write_all(b" align=\"center\"")?;
+                        }
+                        TableAlignment::None => (),
+                    }
                 }
-                NodeValue::FootnoteDefinition(_) => {
-                    // TODO
-                    // if entering {
-                    //     if self.footnote_ix == 0 {
-                    //         self.output
-                    //             .write_all(b"<section class=\"footnotes\">\n<ol>\n")?;
-                    //     }
-                    //     self.footnote_ix += 1;
-                    //     writeln!(self.output, "<li id=\"fn{}\">", self.footnote_ix)?;
-                    // } else {
-                    //     if self.put_footnote_backref()? {
-                    //         self.output.write_all(b"\n")?;
-                    //     }
-                    //     self.output.write_all(b"</li>\n")?;
-                    // }
+                NodeValue::FootnoteDefinition(ref name) => {
+                    write_footnote_name_attr(self.output, name)?;
                 }
                 NodeValue::FootnoteReference(ref r) => {
-                    // TODO
-                    // if entering {
-                    //     let r = str::from_utf8(r).unwrap();
-                    //     write!(
-                    //         self.output,
-                    //         "<sup class=\"footnote-ref\"><a href=\"#fn{}\" id=\"fnref{}\">{}</a></sup>",
-                    //         r, r, r
-                    //     )?;
-                    // }
+                    write_footnote_name_attr(self.output, r)?;
                 }
                 NodeValue::TaskItem(checked) => {
-                    // TODO
-                    // if entering {
-                    //     if checked {
-                    //         self.output.write_all(
-                    //             b"<input type=\"checkbox\" disabled=\"\" checked=\"\" /> ",
-                    //         )?;
-                    //     } else {
-                    //         self.output
-                    //             .write_all(b"<input type=\"checkbox\" disabled=\"\" /> ")?;
-                    //     }
-                    // }
+                    write_task_item_attr(self.output, checked)?;
                 }
             }
 
@@ -369,9 +416,87 @@ impl<'o> XmlFormatter<'o> {
             write!(
                 self.output,
                 "</{}>\n",
-                node.data.borrow().value.xml_node_name()
+                xml_tag_name(&node.data.borrow().value)
             )?;
         }
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn header_table_row_gets_a_distinct_element_name() {
+        assert_eq!(xml_tag_name(&NodeValue::TableRow(true)), "table_header");
+        assert_eq!(xml_tag_name(&NodeValue::TableRow(false)), "table_row");
+    }
+
+    #[test]
+    fn footnote_name_attr_is_escaped() {
+        let mut out = Vec::new();
+        write_footnote_name_attr(&mut out, b"a&b").unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), " name=\"a&amp;b\"");
+    }
+
+    #[test]
+    fn task_item_attr_uses_completed_not_checked() {
+        let mut checked = Vec::new();
+        write_task_item_attr(&mut checked, true).unwrap();
+        assert_eq!(String::from_utf8(checked).unwrap(), " completed=\"true\"");
+
+        let mut unchecked = Vec::new();
+        write_task_item_attr(&mut unchecked, false).unwrap();
+        assert_eq!(String::from_utf8(unchecked).unwrap(), " completed=\"false\"");
+    }
+
+    #[test]
+    fn limited_writer_flushes_closing_tags_after_truncation() {
+        let mut out = Vec::new();
+        let truncated = Rc::new(Cell::new(false));
+        let mut writer = LimitedWriter::new(&mut out, 21, Rc::clone(&truncated));
+
+        writer.write_all(b"<document>").unwrap();
+        writer.write_all(b"<paragraph>").unwrap();
+        // This overflows the 21-byte budget, which should flip `truncated`
+        // and append the ellipsis marker.
+        writer.write_all(b"more text than the budget allows").unwrap();
+        assert!(truncated.get());
+
+        // Once truncated, the formatter only ever issues closing-tag
+        // writes during its post-order unwind; those must still reach the
+        // underlying sink so the output stays well-formed.
+        writer.write_all(b"</paragraph>").unwrap();
+        writer.write_all(b"</document>").unwrap();
+
+        let rendered = String::from_utf8(out).unwrap();
+        assert!(rendered.ends_with("</paragraph></document>"));
+        assert_eq!(
+            rendered.matches("<paragraph>").count(),
+            rendered.matches("</paragraph>").count()
+        );
+        assert_eq!(
+            rendered.matches("<document>").count(),
+            rendered.matches("</document>").count()
+        );
+    }
+
+    #[test]
+    fn limited_writer_truncates_on_a_utf8_char_boundary() {
+        let mut out = Vec::new();
+        let truncated = Rc::new(Cell::new(false));
+        // "caf\u{e9}" is 5 bytes ("caf" + 2-byte 'é'); a budget of 4 lands
+        // right in the middle of that final codepoint.
+        let mut writer = LimitedWriter::new(&mut out, 4, Rc::clone(&truncated));
+
+        writer.write_all("café".as_bytes()).unwrap();
+        assert!(truncated.get());
+
+        // The cut must fall before the split codepoint, not through it, or
+        // the output (still declared `encoding="UTF-8"`) would be invalid.
+        assert_eq!(String::from_utf8(out).unwrap(), "caf...");
+    }
+}